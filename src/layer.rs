@@ -2,6 +2,7 @@ use math::*;
 use phloem::{Blob, Numeric};
 use shared_memory::*;
 use layers::*;
+use net_state::{NetStateRule, NetworkState};
 use std::fmt;
 
 use std::sync::{RwLockReadGuard, RwLockWriteGuard};
@@ -11,11 +12,30 @@ pub type ReadBlob<'_> = RwLockReadGuard<'_, HeapBlob>;
 /// Write access to a Blob via a RwLock
 pub type WriteBlob<'_> = RwLockWriteGuard<'_, HeapBlob>;
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Selects which backend a `Layer`/`Network` computes on.
+pub enum ComputeMode {
+    /// Compute on the CPU, reading/writing Blobs' host memory.
+    Cpu,
+    /// Compute on the GPU, reading/writing Blobs' device memory.
+    Gpu,
+}
+
+/// Makes sure `blob`'s data currently lives where `mode` needs it,
+/// host-device syncing only if it doesn't already.
+pub(crate) fn sync_blob_to(blob: &mut HeapBlob, mode: ComputeMode) {
+    match mode {
+        ComputeMode::Cpu if blob.on_device() => blob.sync_to_host(),
+        ComputeMode::Gpu if !blob.on_device() => blob.sync_to_device(),
+        _ => {}
+    }
+}
+
 #[derive(Debug)]
 /// The generic Layer
-pub struct Layer<'a> {
+pub struct Layer {
     /// The configuration of the Layer
-    pub config: Box<&'a LayerConfig>,
+    pub config: Box<LayerConfig>,
     /// The Layer Interface
     pub worker: Box<ILayer>,
 
@@ -28,14 +48,16 @@ pub struct Layer<'a> {
 
     /// Vector indicating whether to compute the diff of each param blob.
     param_propagate_down: Vec<bool>,
+
+    /// The computation mode (CPU or GPU) this layer currently runs in.
+    mode: ComputeMode,
 }
 
-impl<'a> Layer<'a> {
+impl Layer {
 
     /// Creates a new Layer from a LayerConfig
-    pub fn from_config(config: &'a LayerConfig) -> Layer {
-        let cl = config.clone();
-        let cfg = Box::<&'a LayerConfig>::new(cl);
+    pub fn from_config(config: &LayerConfig) -> Layer {
+        let cfg = Box::new(LayerConfig::clone(config));
         Layer {
             loss: Vec::new(),
             blobs: Vec::new(),
@@ -44,12 +66,15 @@ impl<'a> Layer<'a> {
 
             worker: Layer::worker_from_config(&cfg),
             config: cfg,
+
+            mode: ComputeMode::Cpu,
         }
     }
 
     fn worker_from_config(config: &LayerConfig) -> Box<ILayer> {
         match config.layer_type {
             LayerType::Sigmoid => Box::new(Sigmoid),
+            LayerType::Split => Box::new(::network::Split),
         }
     }
 
@@ -67,30 +92,144 @@ impl<'a> Layer<'a> {
     pub fn loss(&self, id: usize) -> Option<&f32> {
         self.loss.get(id)
     }
+
+    /// Returns the computation mode this layer currently runs in.
+    pub fn mode(&self) -> ComputeMode {
+        self.mode
+    }
+
+    /// Sets the computation mode this layer runs in.
+    pub fn set_mode(&mut self, mode: ComputeMode) {
+        self.mode = mode;
+    }
+
+    /// Runs `self.worker`'s forward pass in the layer's current `mode`,
+    /// giving it access to this layer's own param Blobs (`self.blobs`) so a
+    /// layer with learnable weights can read them.
+    pub fn forward(&self, bottom: &[ArcLock<HeapBlob>], top: &mut Vec<ArcLock<HeapBlob>>) -> f32 {
+        self.worker.forward(bottom, top, &self.blobs, self.mode)
+    }
+
+    /// Runs `self.worker`'s backward pass in the layer's current `mode`,
+    /// giving it access to this layer's own param Blobs so it can accumulate
+    /// a gradient into them. When `self.blobs` is shared with another layer
+    /// (see `ParamConfig::name`), both layers' backward passes write into
+    /// the very same Blob (a layer writes into it through the Blob's own
+    /// interior `RwLock`, not by replacing the `ArcLock` itself), so a
+    /// layer implementation must add its diff in rather than overwrite it.
+    pub fn backward(&self, top: &[HeapBlob], propagate_down: &[bool], bottom: &mut Vec<HeapBlob>) {
+        self.worker.backward(top, propagate_down, bottom, &self.blobs, self.mode)
+    }
 }
 
 /// A Layer in a Neural Network that can handle forward and backward of a computation step.
 pub trait ILayer {
     /// Compute the layer output.
     /// Uses the CPU.
-    fn forward_cpu(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>);
+    ///
+    /// `blobs` are this layer's own param Blobs (`Layer::blobs`), in the
+    /// order declared by `LayerConfig::param`; a layer with no params
+    /// (like `Split`) just ignores it.
+    fn forward_cpu(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>, blobs: &[ArcLock<HeapBlob>]);
     /// Compute the gradients for the bottom blobs
     /// if the corresponding value of propagate_down is true.
     /// Uses the CPU.
-    fn backward_cpu(&self, top: &[HeapBlob], propagate_down: &[bool], bottom: &mut Vec<HeapBlob>);
+    ///
+    /// `blobs` are this layer's own param Blobs. When a param is shared
+    /// with another layer (`ParamConfig::name`), `blobs` holds the very
+    /// same `ArcLock` the sharing layer holds, so this must accumulate its
+    /// gradient into the Blob's diff (`+=`) rather than overwrite it --
+    /// `Network::backward` clears every param's diff once per backward
+    /// pass before any layer runs, precisely so sharing layers can sum into
+    /// it safely.
+    fn backward_cpu(&self,
+                     top: &[HeapBlob],
+                     propagate_down: &[bool],
+                     bottom: &mut Vec<HeapBlob>,
+                     blobs: &[ArcLock<HeapBlob>]);
+
+    /// Compute the layer output.
+    /// Uses the GPU.
+    ///
+    /// Layers without a GPU kernel can leave this at its default, which
+    /// just runs the CPU path -- `forward` only syncs blobs to host memory
+    /// before calling this when `has_gpu_kernel` says there's no real GPU
+    /// kernel to feed, so the CPU path always sees fresh host data.
+    fn forward_gpu(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>, blobs: &[ArcLock<HeapBlob>]) {
+        self.forward_cpu(bottom, top, blobs)
+    }
+    /// Compute the gradients for the bottom blobs.
+    /// Uses the GPU.
+    ///
+    /// See `forward_gpu` for the default CPU fallback.
+    fn backward_gpu(&self,
+                     top: &[HeapBlob],
+                     propagate_down: &[bool],
+                     bottom: &mut Vec<HeapBlob>,
+                     blobs: &[ArcLock<HeapBlob>]) {
+        self.backward_cpu(top, propagate_down, bottom, blobs)
+    }
+
+    /// Whether this layer provides a genuine GPU kernel, i.e. overrides
+    /// `forward_gpu`/`backward_gpu` instead of leaving them at their CPU
+    /// fallback default. Defaults to `false`, since every layer in this
+    /// crate today only ever runs on the CPU.
+    ///
+    /// `forward`/`backward` use this, not the requested `mode`, to decide
+    /// which memory to sync blobs to: `mode` says what the *network* was
+    /// asked to run as, but a layer with no GPU kernel always reads/writes
+    /// host memory regardless, so syncing it to the device under
+    /// `ComputeMode::Gpu` would just leave it reading stale host data.
+    fn has_gpu_kernel(&self) -> bool {
+        false
+    }
 
-    /// Compute the layer output using the currently set computation method (CPU).
-    fn forward(&self, bottom: &[ArcLock<HeapBlob>], top: &mut Vec<ArcLock<HeapBlob>>) -> f32 {
+    /// Sizes this layer's top Blobs (and any internal buffers) from the
+    /// current shapes of its bottom Blobs.
+    ///
+    /// Called at the start of every `forward`, so the net adapts to
+    /// changing input dimensions (e.g. a variable batch size) instead of
+    /// needing top Blobs pre-sized to match. The default is a no-op that
+    /// leaves every top Blob's shape as it is, so a layer whose tops must
+    /// follow its bottoms' shapes overrides it (as `Split` resizes each of
+    /// its tops to match its one bottom -- see `Split::reshape`).
+    fn reshape(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>) {}
+
+    /// Compute the layer output using `mode`, syncing every blob to the
+    /// memory the kernel that actually runs needs beforehand -- host memory
+    /// if `mode` is `Cpu`, or if `mode` is `Gpu` but this layer has no real
+    /// GPU kernel (see `has_gpu_kernel`) and will fall back to the CPU path.
+    fn forward(&self,
+               bottom: &[ArcLock<HeapBlob>],
+               top: &mut Vec<ArcLock<HeapBlob>>,
+               blobs: &[ArcLock<HeapBlob>],
+               mode: ComputeMode)
+               -> f32 {
         // Lock();
-        // Reshape(bottom, top); // Reshape the layer to fit top & bottom blob
         let mut loss = 0f32;
 
+        let sync_mode = if self.has_gpu_kernel() { mode } else { ComputeMode::Cpu };
+
+        for b in bottom {
+            sync_blob_to(&mut b.write().unwrap(), sync_mode);
+        }
+        for t in top.iter() {
+            sync_blob_to(&mut t.write().unwrap(), sync_mode);
+        }
+        for p in blobs {
+            sync_blob_to(&mut p.write().unwrap(), sync_mode);
+        }
+
         let btm: Vec<_> = bottom.iter().map(|b| b.read().unwrap()).collect();
         // let tp: Vec<_> = top.iter().map(|b| b.write().unwrap()).collect();
         let tp_ref = top.iter().map(|t| t.clone()).collect::<Vec<_>>();
         let mut tp = &mut tp_ref.iter().map(|b| b.write().unwrap()).collect::<Vec<_>>();
         let mut tpo = &mut tp.iter_mut().map(|a| a).collect::<Vec<_>>();
-        self.forward_cpu(&btm, tpo);
+        self.reshape(&btm, tpo);
+        match mode {
+            ComputeMode::Cpu => self.forward_cpu(&btm, tpo, blobs),
+            ComputeMode::Gpu => self.forward_gpu(&btm, tpo, blobs),
+        }
         // self.forward_cpu(bottom, top);
 
         for (top_id, top_layer) in top.iter().enumerate() {
@@ -110,6 +249,32 @@ pub trait ILayer {
         loss
     }
 
+    /// Compute the gradients for the bottom blobs using `mode`. `top` and
+    /// `bottom` are plain snapshots, already synced by `Network::backward`
+    /// (to host memory unless this layer has a real GPU kernel) before it
+    /// read them out of their `ArcLock`s, so
+    /// only `blobs` -- still `ArcLock`s at this point -- needs syncing here,
+    /// to whichever memory the kernel that actually runs needs (see
+    /// `has_gpu_kernel`), mirroring `forward`'s syncing of every blob it
+    /// touches.
+    fn backward(&self,
+                top: &[HeapBlob],
+                propagate_down: &[bool],
+                bottom: &mut Vec<HeapBlob>,
+                blobs: &[ArcLock<HeapBlob>],
+                mode: ComputeMode) {
+        let sync_mode = if self.has_gpu_kernel() { mode } else { ComputeMode::Cpu };
+
+        for p in blobs {
+            sync_blob_to(&mut p.write().unwrap(), sync_mode);
+        }
+
+        match mode {
+            ComputeMode::Cpu => self.backward_cpu(top, propagate_down, bottom, blobs),
+            ComputeMode::Gpu => self.backward_gpu(top, propagate_down, bottom, blobs),
+        }
+    }
+
     /// Return whether "anonymous" top blobs are created automatically for the layer.
     ///
     /// If this method returns true, Network::init will create enough "anonymous" top
@@ -150,6 +315,17 @@ pub trait ILayer {
     fn allow_force_backward(&self, bottom_id: usize) -> bool {
         true
     }
+
+    /// Returns the shape this layer wants for the param Blob at `param_id`.
+    ///
+    /// `Network` init allocates a param Blob with this shape before it
+    /// checks whether the param is shared with an earlier layer. Returning
+    /// an empty shape (the default) means the layer has no opinion of its
+    /// own and is happy to take on whatever shape a shared owner already
+    /// has.
+    fn param_shape(&self, param_id: usize) -> Vec<usize> {
+        Vec::new()
+    }
 }
 
 impl fmt::Debug for ILayer {
@@ -158,7 +334,7 @@ impl fmt::Debug for ILayer {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Layer Configuration Struct
 pub struct LayerConfig {
     /// The Name of the Layer
@@ -180,6 +356,15 @@ pub struct LayerConfig {
     /// Specifies on which bottoms the backpropagation should be skipped.
     /// The size must be either 0 or equal to the number of bottoms.
     pub propagate_down: Vec<bool>,
+
+    /// Rules controlling whether this layer is included in the net.
+    /// The layer is included only if every one of these rules (vacuously
+    /// true if none are given) matches the current `NetworkState`.
+    pub include: Vec<NetStateRule>,
+    /// Rules controlling whether this layer is excluded from the net.
+    /// The layer is excluded if any of these rules matches the current
+    /// `NetworkState`.
+    pub exclude: Vec<NetStateRule>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -187,6 +372,12 @@ pub struct LayerConfig {
 pub enum LayerType {
     /// Sigmoid Layer
     Sigmoid,
+    /// Split Layer
+    ///
+    /// Synthesized by `Network`'s `InsertSplits` init pass; never written by
+    /// hand in a `LayerConfig`. Fans a single bottom Blob out to several top
+    /// Blobs, so that every Blob has at most one consumer after the pass.
+    Split,
 }
 
 impl LayerConfig {
@@ -202,9 +393,30 @@ impl LayerConfig {
 
             params: Vec::new(),
             propagate_down: Vec::new(),
+
+            include: Vec::new(),
+            exclude: Vec::new(),
         }
     }
 
+    /// Returns whether this layer should be part of a net built for `state`.
+    ///
+    /// The layer is kept if `state` matches every `include` rule (vacuously
+    /// true if there are none) and it is dropped if `state` matches any
+    /// `exclude` rule. Mirrors Caffe's `FilterNet`/`StateMeetsRule`.
+    pub fn included_in(&self, state: &NetworkState) -> bool {
+        if self.exclude.iter().any(|rule| rule.matches(state)) {
+            return false;
+        }
+
+        self.include.iter().all(|rule| rule.matches(state))
+    }
+
+    /// Returns the type of Layer this config builds.
+    pub fn layer_type(&self) -> LayerType {
+        self.layer_type
+    }
+
     /// Returns the Name of the requested top Blob
     pub fn top(&self, top_id: usize) -> Option<&String> {
         self.tops.get(top_id)
@@ -215,6 +427,12 @@ impl LayerConfig {
         self.tops.len()
     }
 
+    /// Appends the name of a top (output) Blob.
+    pub fn add_top(&mut self, name: &str) -> &mut Self {
+        self.tops.push(name.to_owned());
+        self
+    }
+
     /// Returns the Name of the requested bottom Blob
     pub fn bottom(&self, bottom_id: usize) -> Option<&String> {
         self.bottoms.get(bottom_id)
@@ -225,11 +443,31 @@ impl LayerConfig {
         self.bottoms.len()
     }
 
+    /// Appends the name of a bottom (input) Blob.
+    pub fn add_bottom(&mut self, name: &str) -> &mut Self {
+        self.bottoms.push(name.to_owned());
+        self
+    }
+
+    /// Overwrites the name of an already-present bottom Blob.
+    ///
+    /// Used by `Network`'s `InsertSplits` pass to rewrite a consumer to
+    /// read from a synthesized split output instead of the original Blob.
+    pub fn set_bottom(&mut self, bottom_id: usize, name: String) {
+        self.bottoms[bottom_id] = name;
+    }
+
     /// Returns the requested ParamConfig
     pub fn param(&self, param_id: usize) -> Option<&ParamConfig> {
         self.params.get(param_id)
     }
 
+    /// Appends a training parameter spec.
+    pub fn add_param(&mut self, param: ParamConfig) -> &mut Self {
+        self.params.push(param);
+        self
+    }
+
     /// Returns the number of params
     pub fn params_len(&self) -> usize {
         self.params.len()
@@ -242,7 +480,7 @@ impl LayerConfig {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Specifies training parameters (multipliers on global learning constants,
 /// and the name and other settings used for weight sharing).
 pub struct ParamConfig {
@@ -0,0 +1,13 @@
+extern crate math;
+extern crate phloem;
+extern crate shared_memory;
+extern crate layers;
+
+pub mod layer;
+pub mod net_state;
+pub mod network;
+pub mod persistence;
+
+pub use layer::{ILayer, Layer, LayerConfig, LayerType};
+pub use net_state::{NetStateRule, NetworkState, Phase};
+pub use network::{Network, NetworkConfig};
@@ -0,0 +1,94 @@
+//! Describes the state a `Network` is built for (train vs. test, and which
+//! optional layers are active), so a single `NetworkConfig` can describe
+//! both the train and the test net.
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// The phase a `Network` is being run in.
+pub enum Phase {
+    /// The net is being used for training.
+    Train,
+    /// The net is being used for inference/evaluation.
+    Test,
+}
+
+#[derive(Debug, Clone)]
+/// The state a `Network` is initialized with.
+///
+/// Passed to `NetStateRule::matches` during `Network` init to decide which
+/// layers are included in (or excluded from) the built net.
+pub struct NetworkState {
+    /// The current phase (train or test).
+    pub phase: Phase,
+    /// The current level. Used together with `NetStateRule::min_level`/
+    /// `max_level` to turn layers on or off.
+    pub level: i32,
+    /// The current stages. Used together with `NetStateRule::stage`/
+    /// `not_stage` to turn layers on or off.
+    pub stage: Vec<String>,
+}
+
+impl Default for NetworkState {
+    fn default() -> NetworkState {
+        NetworkState {
+            phase: Phase::Test,
+            level: 0,
+            stage: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// A rule used by a `LayerConfig`'s `include`/`exclude` lists to decide
+/// whether a layer is part of the net for a given `NetworkState`.
+///
+/// Mirrors Caffe's `NetStateRule`: a rule matches a `NetworkState` if every
+/// one of its (optional) conditions holds.
+pub struct NetStateRule {
+    /// The phase the rule requires, if any.
+    pub phase: Option<Phase>,
+    /// The minimum level the rule requires, if any.
+    pub min_level: Option<i32>,
+    /// The maximum level the rule requires, if any.
+    pub max_level: Option<i32>,
+    /// Stages that all must be present for the rule to match.
+    pub stage: Vec<String>,
+    /// Stages that must all be absent for the rule to match.
+    pub not_stage: Vec<String>,
+}
+
+impl NetStateRule {
+    /// Returns whether this rule matches the given `NetworkState`.
+    pub fn matches(&self, state: &NetworkState) -> bool {
+        if let Some(phase) = self.phase {
+            if phase != state.phase {
+                return false;
+            }
+        }
+
+        if let Some(min_level) = self.min_level {
+            if state.level < min_level {
+                return false;
+            }
+        }
+
+        if let Some(max_level) = self.max_level {
+            if state.level > max_level {
+                return false;
+            }
+        }
+
+        for stage in &self.stage {
+            if !state.stage.contains(stage) {
+                return false;
+            }
+        }
+
+        for not_stage in &self.not_stage {
+            if state.stage.contains(not_stage) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
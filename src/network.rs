@@ -0,0 +1,714 @@
+//! Wires `Layer`s together into a directed acyclic graph.
+//!
+//! A `Network` is built from a `NetworkConfig` by resolving every layer's
+//! `bottoms`/`tops` into shared Blobs, mirroring Caffe's `Net::Init`. Once
+//! built, `forward()`/`backward()` run every layer in the order it was
+//! registered (and the reverse order, respectively).
+
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use shared_memory::*;
+use layer::{sync_blob_to, ComputeMode, ILayer, Layer, LayerConfig, LayerType, ReadBlob, WriteBlob};
+use net_state::NetworkState;
+
+#[derive(Debug, Clone)]
+/// Configuration for a `Network`.
+pub struct NetworkConfig {
+    /// The configurations of the `Layer`s that make up the `Network`, in the
+    /// order they should execute.
+    pub layers: Vec<LayerConfig>,
+}
+
+impl NetworkConfig {
+    /// Creates a new, empty `NetworkConfig`.
+    pub fn new() -> NetworkConfig {
+        NetworkConfig { layers: Vec::new() }
+    }
+
+    /// Appends a `LayerConfig` to the network.
+    pub fn add_layer(&mut self, layer: LayerConfig) -> &mut Self {
+        self.layers.push(layer);
+        self
+    }
+}
+
+/// A Network of `Layer`s, wired together into a DAG via shared Blobs.
+///
+/// The `Network` owns every intermediate Blob; each `Layer` only holds
+/// shared references (`ArcLock<HeapBlob>`) into the Blobs it reads from
+/// (`bottom_vecs`) and writes to (`top_vecs`).
+pub struct Network<'a> {
+    /// The configuration this network was built from.
+    pub config: Box<&'a NetworkConfig>,
+
+    /// The layers that make up the network, in registration order, after
+    /// filtering by `NetworkState` and running `insert_splits`. This can be
+    /// longer than `config.layers` -- every Blob with more than one
+    /// consumer gets a synthesized `Split` layer. See `insert_splits` for
+    /// details; after this pass, every Blob has at most one consumer.
+    pub(crate) effective_layers: Vec<LayerConfig>,
+    /// The layers that make up the network, matching `effective_layers`
+    /// one to one.
+    pub(crate) layers: Vec<Layer>,
+
+    /// Every Blob owned by the network, indexed by `blob_names_index`.
+    blobs: Vec<ArcLock<HeapBlob>>,
+    /// Maps a Blob name to its index into `blobs`.
+    blob_names_index: HashMap<String, usize>,
+
+    /// For every layer, the Blobs that feed into it.
+    bottom_vecs: Vec<Vec<ArcLock<HeapBlob>>>,
+    /// For every layer, the Blobs it writes to.
+    top_vecs: Vec<Vec<ArcLock<HeapBlob>>>,
+
+    /// Whether each layer needs to compute a backward pass.
+    layer_need_backward: Vec<bool>,
+    /// Whether each owned Blob needs its diff computed.
+    blob_need_backward: Vec<bool>,
+
+    /// Every learnable param Blob across the network, in the order each was
+    /// first registered. A shared param appears here only once, under the
+    /// index of the layer that first declared its name.
+    params: Vec<ArcLock<HeapBlob>>,
+    /// The owning layer's name for each entry in `params`, used to build
+    /// descriptive dimension-mismatch errors.
+    param_owners: Vec<String>,
+    /// Maps a non-empty `ParamConfig::name` to the index into `params` of
+    /// the Blob that owns it.
+    param_names_index: HashMap<String, usize>,
+
+    /// The computation mode (CPU or GPU) every layer in this network runs
+    /// in.
+    mode: ComputeMode,
+}
+
+impl<'a> Network<'a> {
+    /// Creates and initializes a `Network` from a `NetworkConfig`, keeping
+    /// only the layers whose `include`/`exclude` rules match `state`. This
+    /// is how a single `NetworkConfig` can describe both the train and the
+    /// test net: build it once per `NetworkState`.
+    ///
+    /// Returns a descriptive error if a layer's `bottoms`/`tops` don't
+    /// satisfy its `exact_num_bottom_blobs`/`exact_num_top_blobs`/
+    /// `min_top_blobs` contract, or if a bottom Blob is never produced by
+    /// an earlier layer.
+    pub fn from_config(config: &'a NetworkConfig, state: &NetworkState) -> Result<Network<'a>, String> {
+        let mut network = Network {
+            config: Box::new(config),
+
+            effective_layers: Vec::new(),
+            layers: Vec::new(),
+
+            blobs: Vec::new(),
+            blob_names_index: HashMap::new(),
+
+            bottom_vecs: Vec::new(),
+            top_vecs: Vec::new(),
+
+            layer_need_backward: Vec::new(),
+            blob_need_backward: Vec::new(),
+
+            params: Vec::new(),
+            param_owners: Vec::new(),
+            param_names_index: HashMap::new(),
+
+            mode: ComputeMode::Cpu,
+        };
+
+        network.init(state)?;
+        Ok(network)
+    }
+
+    /// Returns the computation mode every layer in this network runs in.
+    pub fn mode(&self) -> ComputeMode {
+        self.mode
+    }
+
+    /// Switches every layer in this network to compute in `mode`.
+    pub fn set_mode(&mut self, mode: ComputeMode) {
+        self.mode = mode;
+        for layer in &mut self.layers {
+            layer.set_mode(mode);
+        }
+    }
+
+    /// Builds the DAG: filters out layers that don't match `state`, runs
+    /// `insert_splits` so every Blob ends up with at most one consumer,
+    /// validates (and auto-completes) every remaining layer's blob-count
+    /// contract, resolves its `bottoms`/`tops` into shared Blobs, sets up
+    /// (and shares) each layer's param Blobs, and figures out which Blobs
+    /// need backpropagation.
+    fn init(&mut self, state: &NetworkState) -> Result<(), String> {
+        let filtered: Vec<LayerConfig> = self.config
+            .layers
+            .iter()
+            .filter(|layer_config| layer_config.included_in(state))
+            .map(LayerConfig::clone)
+            .collect();
+
+        // A local, owned copy -- not a field of `self` -- so that iterating
+        // over it doesn't keep `self` borrowed while we call back into
+        // `&mut self` methods below.
+        let mut effective = Self::insert_splits(filtered);
+
+        for layer_config in &mut effective {
+            let mut layer = Layer::from_config(layer_config);
+
+            Self::validate_blob_counts(layer_config, &layer)?;
+
+            // validate_blob_counts may have just auto-added anonymous top
+            // names to layer_config (for a layer with auto_top_blobs()),
+            // which Layer::from_config cloned into layer.config before that
+            // happened -- keep the two in sync rather than leaving
+            // layer.config describing an incomplete, pre-validation layer.
+            layer.config = Box::new(layer_config.clone());
+
+            let layer_id = self.layers.len();
+
+            let bottoms = self.bottom_blobs(layer_config)?;
+            let tops = self.top_blobs(layer_config);
+
+            self.bottom_vecs.push(bottoms);
+            self.top_vecs.push(tops);
+            self.layers.push(layer);
+
+            self.setup_params(layer_id, layer_config)?;
+        }
+
+        self.effective_layers = effective;
+
+        self.init_backward();
+
+        Ok(())
+    }
+
+    /// Checks `layer_config` against `layer.worker`'s
+    /// `exact_num_bottom_blobs`/`exact_num_top_blobs`/`min_top_blobs`
+    /// contract, auto-creating anonymous top blobs first if the layer opts
+    /// into that via `auto_top_blobs`. Returns a descriptive error instead
+    /// of letting a mismatch panic later during `forward`/`backward`.
+    fn validate_blob_counts(layer_config: &mut LayerConfig, layer: &Layer) -> Result<(), String> {
+        let exact_bottom = layer.worker.exact_num_bottom_blobs();
+        if exact_bottom > 0 && layer_config.bottoms_len() != exact_bottom {
+            return Err(format!("Layer '{}' takes {} bottom blob(s) as input, but {} were given",
+                                layer_config.name,
+                                exact_bottom,
+                                layer_config.bottoms_len()));
+        }
+
+        if layer.worker.auto_top_blobs() {
+            let needed = cmp::max(layer.worker.exact_num_top_blobs(), layer.worker.min_top_blobs());
+            while layer_config.tops_len() < needed {
+                let top_id = layer_config.tops_len();
+                layer_config.add_top(&format!("{}.top_{}", layer_config.name, top_id));
+            }
+        }
+
+        let exact_top = layer.worker.exact_num_top_blobs();
+        if exact_top > 0 && layer_config.tops_len() != exact_top {
+            return Err(format!("Layer '{}' produces {} top blob(s), but {} were given",
+                                layer_config.name,
+                                exact_top,
+                                layer_config.tops_len()));
+        }
+
+        let min_top = layer.worker.min_top_blobs();
+        if layer_config.tops_len() < min_top {
+            return Err(format!("Layer '{}' requires at least {} top blob(s), but only {} were given",
+                                layer_config.name,
+                                min_top,
+                                layer_config.tops_len()));
+        }
+
+        Ok(())
+    }
+
+    /// Inserts a synthesized `Split` layer after every Blob that's consumed
+    /// as a bottom by more than one layer, and rewrites each of its
+    /// consumers to read its own dedicated split output instead. Mirrors
+    /// Caffe's `InsertSplits`.
+    ///
+    /// Invariant this establishes: after this pass, every Blob has at most
+    /// one consumer, so backward accumulation never has to sum diffs for a
+    /// single Blob coming from more than one layer -- a `Split` layer's own
+    /// `backward_cpu` does that summation instead.
+    fn insert_splits(layers: Vec<LayerConfig>) -> Vec<LayerConfig> {
+        let mut consumer_count: HashMap<String, usize> = HashMap::new();
+        for layer in &layers {
+            for bottom_id in 0..layer.bottoms_len() {
+                *consumer_count.entry(layer.bottom(bottom_id).unwrap().clone()).or_insert(0) += 1;
+            }
+        }
+
+        // Maps a blob name to the `(producer_name, top_id)` that produced
+        // it, so a later consumer can derive the exact split output name a
+        // producer's `Split` layer will hand it.
+        let mut blob_origin: HashMap<String, (String, usize)> = HashMap::new();
+        // How many of a blob's split outputs have already been handed to a
+        // consumer, so each one gets a distinct `_split_<k>` suffix.
+        let mut handed_out: HashMap<String, usize> = HashMap::new();
+
+        let mut result = Vec::with_capacity(layers.len());
+        for mut layer in layers {
+            for bottom_id in 0..layer.bottoms_len() {
+                let name = layer.bottom(bottom_id).unwrap().clone();
+                if consumer_count.get(&name).cloned().unwrap_or(0) > 1 {
+                    let (producer, top_id) = blob_origin[&name].clone();
+                    let k = *handed_out.get(&name).unwrap_or(&0);
+                    handed_out.insert(name.clone(), k + 1);
+                    layer.set_bottom(bottom_id, format!("{}_{}_{}_split_{}", name, producer, top_id, k));
+                }
+            }
+
+            let mut splits = Vec::new();
+            for top_id in 0..layer.tops_len() {
+                let name = layer.top(top_id).unwrap().clone();
+                blob_origin.insert(name.clone(), (layer.name.clone(), top_id));
+
+                let n = consumer_count.get(&name).cloned().unwrap_or(0);
+                if n > 1 {
+                    let mut split = LayerConfig::new(format!("{}_{}_split", layer.name, top_id), ::layer::LayerType::Split);
+                    split.add_bottom(&name);
+                    for k in 0..n {
+                        split.add_top(&format!("{}_{}_{}_split_{}", name, layer.name, top_id, k));
+                    }
+                    splits.push(split);
+                }
+            }
+
+            result.push(layer);
+            result.extend(splits);
+        }
+
+        result
+    }
+
+    /// Sets up `layer_id`'s param Blobs, sharing with an earlier layer that
+    /// declared the same (non-empty) `ParamConfig::name` after checking the
+    /// shapes agree (per `ParamConfig::share_mode`). A shared param's Blob
+    /// is the very same `ArcLock` as the owner's, so a write through either
+    /// layer's `blobs` is visible to both -- which is also how backward
+    /// accumulates diffs from every sharing layer into one Blob.
+    fn setup_params(&mut self, layer_id: usize, layer_config: &LayerConfig) -> Result<(), String> {
+        for param_id in 0..layer_config.params_len() {
+            let param_config = layer_config.param(param_id).unwrap();
+
+            let mut candidate = HeapBlob::new();
+            candidate.reshape(self.layers[layer_id].worker.param_shape(param_id));
+
+            let blob = if !param_config.name.is_empty() && self.param_names_index.contains_key(&param_config.name) {
+                let owner_id = self.param_names_index[&param_config.name];
+                let owner_blob = self.params[owner_id].clone();
+
+                // An empty `param_shape` has no opinion of its own, so it
+                // takes on the owner's shape without a dimension check.
+                if !candidate.shape().is_empty() {
+                    let owner = owner_blob.read().unwrap();
+                    param_config.check_dimensions(&candidate,
+                                                   &*owner,
+                                                   param_config.name.clone(),
+                                                   self.param_owners[owner_id].clone(),
+                                                   layer_config.name.clone())?;
+                }
+
+                owner_blob
+            } else {
+                let blob: ArcLock<HeapBlob> = Arc::new(RwLock::new(candidate));
+
+                if !param_config.name.is_empty() {
+                    self.param_names_index.insert(param_config.name.clone(), self.params.len());
+                }
+                self.params.push(blob.clone());
+                self.param_owners.push(layer_config.name.clone());
+
+                blob
+            };
+
+            self.layers[layer_id].blobs.push(blob);
+        }
+
+        Ok(())
+    }
+
+    /// Resolves a layer's `bottoms` into already-registered Blobs.
+    fn bottom_blobs(&mut self, layer_config: &LayerConfig) -> Result<Vec<ArcLock<HeapBlob>>, String> {
+        (0..layer_config.bottoms_len())
+            .map(|bottom_id| {
+                let name = layer_config.bottom(bottom_id).unwrap();
+                let idx = *self.blob_names_index
+                    .get(name)
+                    .ok_or_else(|| {
+                        format!("Unknown bottom blob '{}' for layer '{}' -- it was never produced as a top",
+                                name,
+                                layer_config.name)
+                    })?;
+                Ok(self.blobs[idx].clone())
+            })
+            .collect()
+    }
+
+    /// Resolves a layer's `tops`, creating a new Blob the first time a name
+    /// is produced and reusing the existing one otherwise (in-place layers).
+    fn top_blobs(&mut self, layer_config: &LayerConfig) -> Vec<ArcLock<HeapBlob>> {
+        (0..layer_config.tops_len())
+            .map(|top_id| {
+                let name = layer_config.top(top_id).unwrap().to_owned();
+
+                if let Some(&idx) = self.blob_names_index.get(&name) {
+                    self.blobs[idx].clone()
+                } else {
+                    let blob: ArcLock<HeapBlob> = Arc::new(RwLock::new(HeapBlob::new()));
+                    let idx = self.blobs.len();
+                    self.blobs.push(blob.clone());
+                    self.blob_names_index.insert(name, idx);
+                    blob
+                }
+            })
+            .collect()
+    }
+
+    /// Computes `layer_need_backward` and `blob_need_backward` by
+    /// propagating `propagate_down` forward from the inputs: a layer needs
+    /// backward if it owns learnable params or any of its (propagated-down)
+    /// bottoms need backward, and in that case every Blob it produces needs
+    /// backward too. Layers are not pruned by whether their outputs reach a
+    /// loss: a layer that meets the rule above is marked even if nothing
+    /// downstream of it consumes its tops.
+    fn init_backward(&mut self) {
+        self.blob_need_backward = vec![false; self.blobs.len()];
+        self.layer_need_backward = Vec::with_capacity(self.layers.len());
+
+        for layer_id in 0..self.layers.len() {
+            let mut needs_backward = !self.layers[layer_id].blobs.is_empty();
+
+            let layer_config = &self.effective_layers[layer_id];
+            for (bottom_id, bottom) in self.bottom_vecs[layer_id].iter().enumerate() {
+                let propagate = layer_config.propagate_down.get(bottom_id).cloned().unwrap_or(true);
+                if propagate && self.blob_need_backward[Self::blob_index(&self.blobs, bottom)] {
+                    needs_backward = true;
+                }
+            }
+
+            if needs_backward {
+                for top in &self.top_vecs[layer_id] {
+                    let idx = Self::blob_index(&self.blobs, top);
+                    self.blob_need_backward[idx] = true;
+                }
+            }
+
+            self.layer_need_backward.push(needs_backward);
+        }
+    }
+
+    fn blob_index(blobs: &[ArcLock<HeapBlob>], blob: &ArcLock<HeapBlob>) -> usize {
+        blobs.iter()
+            .position(|candidate| Arc::ptr_eq(candidate, blob))
+            .expect("blob not registered with this network")
+    }
+
+    /// Runs a forward pass over every layer, in registration order, and
+    /// returns the accumulated loss.
+    pub fn forward(&mut self) -> f32 {
+        let mut loss = 0f32;
+
+        for layer_id in 0..self.layers.len() {
+            loss += self.layers[layer_id].forward(&self.bottom_vecs[layer_id], &mut self.top_vecs[layer_id]);
+        }
+
+        loss
+    }
+
+    /// Runs a backward pass over every layer that needs one, in reverse
+    /// registration order, propagating gradients from the loss back to the
+    /// inputs.
+    ///
+    /// Clears every param Blob's diff first (mirroring Caffe's
+    /// `ClearParamDiffs`), so that when a param is shared between layers
+    /// (`setup_params`), each sharing layer's `backward_cpu` can safely
+    /// accumulate its gradient into the one Blob they share instead of the
+    /// second layer's diff stomping the first's.
+    pub fn backward(&mut self) {
+        for param in &self.params {
+            let mut param = param.write().unwrap();
+            for value in param.mut_cpu_diff().iter_mut() {
+                *value = 0.0;
+            }
+        }
+
+        for layer_id in (0..self.layers.len()).rev() {
+            if !self.layer_need_backward[layer_id] {
+                continue;
+            }
+
+            let layer_config = &self.effective_layers[layer_id];
+            let propagate_down: Vec<bool> = (0..self.bottom_vecs[layer_id].len())
+                .map(|bottom_id| layer_config.propagate_down.get(bottom_id).cloned().unwrap_or(true))
+                .collect();
+
+            // Sync to host memory unless this layer has a genuine GPU
+            // kernel to feed -- otherwise it always reads/writes host
+            // memory regardless of `self.mode`, and syncing it to the
+            // device would just leave it reading stale host data.
+            let sync_mode = if self.layers[layer_id].worker.has_gpu_kernel() {
+                self.mode
+            } else {
+                ComputeMode::Cpu
+            };
+            for blob in self.top_vecs[layer_id].iter().chain(self.bottom_vecs[layer_id].iter()) {
+                sync_blob_to(&mut blob.write().unwrap(), sync_mode);
+            }
+
+            let tops: Vec<HeapBlob> = self.top_vecs[layer_id]
+                .iter()
+                .map(|blob| blob.read().unwrap().clone())
+                .collect();
+            let mut bottoms: Vec<HeapBlob> = self.bottom_vecs[layer_id]
+                .iter()
+                .map(|blob| blob.read().unwrap().clone())
+                .collect();
+
+            self.layers[layer_id].backward(&tops, &propagate_down, &mut bottoms);
+
+            for (bottom, updated) in self.bottom_vecs[layer_id].iter().zip(bottoms) {
+                *bottom.write().unwrap() = updated;
+            }
+        }
+    }
+}
+
+/// A layer that fans its single bottom Blob out to several top Blobs.
+///
+/// Synthesized internally by `Network::insert_splits`; never constructed by
+/// hand. Its `forward_cpu` copies the bottom's data into every top;
+/// its `backward_cpu` sums every top's diff back into the bottom's diff, so
+/// a Blob consumed by several layers still gets a single, correctly
+/// accumulated gradient.
+pub struct Split;
+
+impl ILayer for Split {
+    fn forward_cpu(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>, _blobs: &[ArcLock<HeapBlob>]) {
+        let data = bottom[0].cpu_data().to_owned();
+        for output in top.iter_mut() {
+            output.mut_cpu_data().copy_from_slice(&data);
+        }
+    }
+
+    fn backward_cpu(&self,
+                     top: &[HeapBlob],
+                     propagate_down: &[bool],
+                     bottom: &mut Vec<HeapBlob>,
+                     _blobs: &[ArcLock<HeapBlob>]) {
+        if !propagate_down.get(0).cloned().unwrap_or(true) {
+            return;
+        }
+
+        let diff = bottom[0].mut_cpu_diff();
+        for value in diff.iter_mut() {
+            *value = 0.0;
+        }
+
+        for output in top {
+            for (sum, value) in diff.iter_mut().zip(output.cpu_diff()) {
+                *sum += *value;
+            }
+        }
+    }
+
+    /// Resizes each top to the bottom's shape -- the tops start out as
+    /// freshly created, zero-capacity Blobs, and `forward_cpu`'s
+    /// `copy_from_slice` requires every top to already be sized to match.
+    fn reshape(&self, bottom: &[ReadBlob], top: &mut Vec<&mut WriteBlob>) {
+        let shape = bottom[0].shape().to_owned();
+        for output in top.iter_mut() {
+            output.reshape(shape.clone());
+        }
+    }
+
+    fn exact_num_bottom_blobs(&self) -> usize {
+        1
+    }
+
+    fn min_top_blobs(&self) -> usize {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use layer::ParamConfig;
+
+    /// Builds an empty `Network` around `config` without running `init` --
+    /// lets a test drive `Network`'s private setup methods (like
+    /// `setup_params`) directly, without first having to wire up bottoms and
+    /// tops through the public `from_config` path.
+    fn bare_network(config: &NetworkConfig) -> Network {
+        Network {
+            config: Box::new(config),
+
+            effective_layers: Vec::new(),
+            layers: Vec::new(),
+
+            blobs: Vec::new(),
+            blob_names_index: HashMap::new(),
+
+            bottom_vecs: Vec::new(),
+            top_vecs: Vec::new(),
+
+            layer_need_backward: Vec::new(),
+            blob_need_backward: Vec::new(),
+
+            params: Vec::new(),
+            param_owners: Vec::new(),
+            param_names_index: HashMap::new(),
+
+            mode: ComputeMode::Cpu,
+        }
+    }
+
+    #[test]
+    fn setup_params_shares_named_param_blobs_across_layers() {
+        let config = NetworkConfig::new();
+        let mut network = bare_network(&config);
+
+        let mut layer_a = LayerConfig::new("a".to_owned(), LayerType::Sigmoid);
+        layer_a.add_param(ParamConfig { name: "shared_w".to_owned(), ..ParamConfig::default() });
+        let mut layer_b = LayerConfig::new("b".to_owned(), LayerType::Sigmoid);
+        layer_b.add_param(ParamConfig { name: "shared_w".to_owned(), ..ParamConfig::default() });
+
+        network.layers.push(Layer::from_config(&layer_a));
+        network.setup_params(0, &layer_a).unwrap();
+
+        network.layers.push(Layer::from_config(&layer_b));
+        network.setup_params(1, &layer_b).unwrap();
+
+        // Both layers declared the same non-empty param name, so they must
+        // end up holding the very same Blob -- and the shared registry
+        // (`params`) must only track it once, under its first owner.
+        assert!(Arc::ptr_eq(&network.layers[0].blobs[0], &network.layers[1].blobs[0]));
+        assert_eq!(network.params.len(), 1);
+        assert_eq!(network.param_owners, vec!["a".to_owned()]);
+    }
+
+    #[test]
+    fn network_config_to_text_from_text_round_trips_topology() {
+        let mut layer_config = LayerConfig::new("producer".to_owned(), LayerType::Sigmoid);
+        layer_config.add_top("out");
+        layer_config.add_param(ParamConfig { name: "w".to_owned(), ..ParamConfig::default() });
+
+        let mut consumer = LayerConfig::new("consumer".to_owned(), LayerType::Split);
+        consumer.add_bottom("out");
+
+        let mut config = NetworkConfig::new();
+        config.add_layer(layer_config);
+        config.add_layer(consumer);
+
+        let reloaded = NetworkConfig::from_text(&config.to_text()).unwrap();
+
+        assert_eq!(reloaded.layers.len(), 2);
+        assert_eq!(reloaded.layers[0].name, "producer");
+        assert!(matches!(reloaded.layers[0].layer_type(), LayerType::Sigmoid));
+        assert_eq!(reloaded.layers[0].top(0), Some(&"out".to_owned()));
+        assert_eq!(reloaded.layers[0].param(0).unwrap().name, "w");
+        assert_eq!(reloaded.layers[1].name, "consumer");
+        assert!(matches!(reloaded.layers[1].layer_type(), LayerType::Split));
+        assert_eq!(reloaded.layers[1].bottom(0), Some(&"out".to_owned()));
+    }
+
+    #[test]
+    fn save_and_load_weights_round_trips_shared_checkpoint() {
+        let mut layer_config = LayerConfig::new("scale".to_owned(), LayerType::Sigmoid);
+        layer_config.add_param(ParamConfig { name: "w".to_owned(), ..ParamConfig::default() });
+
+        let mut config = NetworkConfig::new();
+        config.add_layer(layer_config.clone());
+
+        let mut saved = bare_network(&config);
+        saved.effective_layers.push(layer_config.clone());
+        saved.layers.push(Layer::from_config(&layer_config));
+        let mut param = HeapBlob::new();
+        param.reshape(vec![2]);
+        param.mut_cpu_data().copy_from_slice(&[4.0, 5.0]);
+        saved.layers[0].blobs.push(Arc::new(RwLock::new(param)));
+
+        let path = ::std::env::temp_dir().join(format!("juice-checkpoint-round-trip-{}.bin", ::std::process::id()));
+        saved.save(&path).unwrap();
+
+        // The embedded topology must describe the same net `saved` was
+        // built from, not just whatever `loaded`'s caller happens to wire
+        // up -- `load_checkpoint_topology` is the only thing standing
+        // between a checkpoint and a `Network` to load it into.
+        let loaded_config = NetworkConfig::load_checkpoint_topology(&path).unwrap();
+        assert_eq!(loaded_config.layers.len(), 1);
+        assert_eq!(loaded_config.layers[0].name, "scale");
+
+        let mut loaded = bare_network(&loaded_config);
+        loaded.effective_layers.push(layer_config.clone());
+        loaded.layers.push(Layer::from_config(&layer_config));
+        loaded.layers[0].blobs.push(Arc::new(RwLock::new(HeapBlob::new())));
+
+        loaded.load_weights(&path).unwrap();
+
+        ::std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.layers[0].blobs[0].read().unwrap().cpu_data().to_vec(),
+                   vec![4.0, 5.0]);
+    }
+
+    #[test]
+    fn split_forward_reshapes_tops_to_match_bottom_before_copying() {
+        let mut bottom_blob = HeapBlob::new();
+        bottom_blob.reshape(vec![3]);
+        bottom_blob.mut_cpu_data().copy_from_slice(&[1.0, 2.0, 3.0]);
+        let bottom: ArcLock<HeapBlob> = Arc::new(RwLock::new(bottom_blob));
+
+        let mut top: Vec<ArcLock<HeapBlob>> = vec![Arc::new(RwLock::new(HeapBlob::new())),
+                                                     Arc::new(RwLock::new(HeapBlob::new()))];
+
+        let layer = Layer::from_config(&LayerConfig::new("split".to_owned(), LayerType::Split));
+
+        // Before `reshape` sized each top from the bottom, both tops stayed
+        // at their freshly created zero capacity and this panicked on a
+        // slice-length mismatch inside `forward_cpu`'s `copy_from_slice`.
+        layer.forward(&[bottom], &mut top);
+
+        for output in &top {
+            assert_eq!(output.read().unwrap().cpu_data().to_vec(), vec![1.0, 2.0, 3.0]);
+        }
+    }
+
+    #[test]
+    fn insert_splits_gives_every_multi_consumer_blob_its_own_split() {
+        let mut producer = LayerConfig::new("producer".to_owned(), LayerType::Sigmoid);
+        producer.add_top("shared");
+
+        let mut consumer_a = LayerConfig::new("consumer_a".to_owned(), LayerType::Sigmoid);
+        consumer_a.add_bottom("shared");
+
+        let mut consumer_b = LayerConfig::new("consumer_b".to_owned(), LayerType::Sigmoid);
+        consumer_b.add_bottom("shared");
+
+        let effective = Network::insert_splits(vec![producer, consumer_a, consumer_b]);
+
+        // The two original consumers, the producer, and one synthesized
+        // `Split` standing in between them.
+        assert_eq!(effective.len(), 4);
+
+        let split = effective.iter()
+            .find(|layer| matches!(layer.layer_type(), LayerType::Split))
+            .expect("insert_splits should have synthesized a Split layer for 'shared'");
+        assert_eq!(split.bottom(0), Some(&"shared".to_owned()));
+        assert_eq!(split.tops_len(), 2);
+
+        let rewritten_a = effective.iter().find(|layer| layer.name == "consumer_a").unwrap();
+        let rewritten_b = effective.iter().find(|layer| layer.name == "consumer_b").unwrap();
+
+        // Every consumer must read its own dedicated split output -- after
+        // this pass, no Blob may have more than one consumer.
+        assert_eq!(rewritten_a.bottom(0), split.top(0));
+        assert_eq!(rewritten_b.bottom(0), split.top(1));
+        assert_ne!(rewritten_a.bottom(0), rewritten_b.bottom(0));
+    }
+}
@@ -0,0 +1,452 @@
+//! Saving and loading `Network`s to and from disk.
+//!
+//! Mirrors Caffe's two serialization formats: a human-readable text form for
+//! a `NetworkConfig`'s topology (so it can be hand-edited, the way a prototxt
+//! can), and a compact binary checkpoint that bundles that same topology
+//! with every trained param Blob's shape and data, keyed by the param's own
+//! `ParamConfig::name` (or, for an unnamed param, `"<layer>.param_<id>"`,
+//! built from the name of the layer that owns it) --
+//! so a checkpoint can be loaded into a renamed or otherwise finetuned net by
+//! matching names rather than positions.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use layer::{DimCheckMode, LayerConfig, LayerType, ParamConfig};
+use net_state::{NetStateRule, Phase};
+use network::{Network, NetworkConfig};
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn layer_type_name(layer_type: LayerType) -> &'static str {
+    match layer_type {
+        LayerType::Sigmoid => "Sigmoid",
+        LayerType::Split => "Split",
+    }
+}
+
+fn parse_layer_type(name: &str) -> Result<LayerType, String> {
+    match name {
+        "Sigmoid" => Ok(LayerType::Sigmoid),
+        "Split" => Ok(LayerType::Split),
+        other => Err(format!("unknown layer type '{}'", other)),
+    }
+}
+
+fn share_mode_name(mode: DimCheckMode) -> &'static str {
+    match mode {
+        DimCheckMode::Strict => "Strict",
+        DimCheckMode::Permissive => "Permissive",
+    }
+}
+
+fn parse_share_mode(name: &str) -> Result<DimCheckMode, String> {
+    match name {
+        "Strict" => Ok(DimCheckMode::Strict),
+        "Permissive" => Ok(DimCheckMode::Permissive),
+        other => Err(format!("unknown share_mode '{}'", other)),
+    }
+}
+
+/// Splits a `key: value` line (after indentation has been trimmed) into its
+/// key and its value, the value unquoted if it was wrapped in `"..."`.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let colon = line.find(':')?;
+    let key = line[..colon].trim();
+    let mut value = line[colon + 1..].trim();
+    if value.starts_with('"') && value.ends_with('"') && value.len() >= 2 {
+        value = &value[1..value.len() - 1];
+    }
+    Some((key, value))
+}
+
+impl NetworkConfig {
+    /// Renders this config's topology as Caffe-prototxt-like text: one
+    /// `layer { ... }` block per `LayerConfig`, in order.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        for layer in &self.layers {
+            out.push_str("layer {\n");
+            out.push_str(&format!("  name: \"{}\"\n", layer.name));
+            out.push_str(&format!("  type: {}\n", layer_type_name(layer.layer_type())));
+
+            for bottom_id in 0..layer.bottoms_len() {
+                out.push_str(&format!("  bottom: \"{}\"\n", layer.bottom(bottom_id).unwrap()));
+            }
+            for top_id in 0..layer.tops_len() {
+                out.push_str(&format!("  top: \"{}\"\n", layer.top(top_id).unwrap()));
+            }
+            for &propagate in &layer.propagate_down {
+                out.push_str(&format!("  propagate_down: {}\n", propagate));
+            }
+
+            for param_id in 0..layer.params_len() {
+                let param = layer.param(param_id).unwrap();
+                out.push_str("  param {\n");
+                out.push_str(&format!("    name: \"{}\"\n", param.name));
+                out.push_str(&format!("    share_mode: {}\n", share_mode_name(param.share_mode)));
+                if let Some(lr_mult) = param.lr_mult {
+                    out.push_str(&format!("    lr_mult: {}\n", lr_mult));
+                }
+                if let Some(decay_mult) = param.decay_mult {
+                    out.push_str(&format!("    decay_mult: {}\n", decay_mult));
+                }
+                out.push_str("  }\n");
+            }
+
+            for rule in &layer.include {
+                out.push_str("  include {\n");
+                write_net_state_rule(&mut out, rule);
+                out.push_str("  }\n");
+            }
+            for rule in &layer.exclude {
+                out.push_str("  exclude {\n");
+                write_net_state_rule(&mut out, rule);
+                out.push_str("  }\n");
+            }
+
+            out.push_str("}\n");
+        }
+
+        out
+    }
+
+    /// Parses a `NetworkConfig` back out of text produced by `to_text`.
+    pub fn from_text(text: &str) -> Result<NetworkConfig, String> {
+        let mut config = NetworkConfig::new();
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+
+        while let Some(line) = lines.next() {
+            if line == "layer {" {
+                config.add_layer(parse_layer_block(&mut lines)?);
+            } else {
+                return Err(format!("expected 'layer {{', found '{}'", line));
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Writes this config's topology to `path` as human-readable text.
+    pub fn save_text<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        File::create(path)?.write_all(self.to_text().as_bytes())
+    }
+
+    /// Reads a `NetworkConfig`'s topology back from text written by
+    /// `save_text`.
+    pub fn load_text<P: AsRef<Path>>(path: P) -> io::Result<NetworkConfig> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        NetworkConfig::from_text(&text).map_err(invalid_data)
+    }
+
+    /// Reads just the topology embedded in a binary checkpoint written by
+    /// `Network::save`, leaving the param data that follows it unread.
+    ///
+    /// `Network` only ever borrows the `NetworkConfig` it's built from (see
+    /// `Network::from_config`), so reconstructing a saved net is two steps,
+    /// not one: build a `Network` from the `NetworkConfig` this returns,
+    /// then call `Network::load_weights` with the same `path` to fill in
+    /// its param Blobs.
+    pub fn load_checkpoint_topology<P: AsRef<Path>>(path: P) -> io::Result<NetworkConfig> {
+        let mut file = File::open(path)?;
+        let text = read_string(&mut file)?;
+        NetworkConfig::from_text(&text).map_err(invalid_data)
+    }
+}
+
+fn write_net_state_rule(out: &mut String, rule: &NetStateRule) {
+    if let Some(phase) = rule.phase {
+        out.push_str(&format!("    phase: {}\n", if phase == Phase::Train { "Train" } else { "Test" }));
+    }
+    if let Some(min_level) = rule.min_level {
+        out.push_str(&format!("    min_level: {}\n", min_level));
+    }
+    if let Some(max_level) = rule.max_level {
+        out.push_str(&format!("    max_level: {}\n", max_level));
+    }
+    for stage in &rule.stage {
+        out.push_str(&format!("    stage: \"{}\"\n", stage));
+    }
+    for not_stage in &rule.not_stage {
+        out.push_str(&format!("    not_stage: \"{}\"\n", not_stage));
+    }
+}
+
+fn parse_net_state_rule<'a, I: Iterator<Item = &'a str>>(lines: &mut I) -> Result<NetStateRule, String> {
+    let mut rule = NetStateRule::default();
+
+    loop {
+        let line = lines.next().ok_or_else(|| "unexpected end of input inside rule block".to_owned())?;
+        if line == "}" {
+            return Ok(rule);
+        }
+
+        let (key, value) = key_value(line).ok_or_else(|| format!("malformed line '{}'", line))?;
+        match key {
+            "phase" => rule.phase = Some(if value == "Train" { Phase::Train } else { Phase::Test }),
+            "min_level" => rule.min_level = Some(value.parse().map_err(|_| format!("invalid min_level '{}'", value))?),
+            "max_level" => rule.max_level = Some(value.parse().map_err(|_| format!("invalid max_level '{}'", value))?),
+            "stage" => rule.stage.push(value.to_owned()),
+            "not_stage" => rule.not_stage.push(value.to_owned()),
+            other => return Err(format!("unknown NetStateRule field '{}'", other)),
+        }
+    }
+}
+
+fn parse_layer_block<'a, I: Iterator<Item = &'a str>>(lines: &mut I) -> Result<LayerConfig, String> {
+    let mut name: Option<String> = None;
+    let mut layer_type: Option<LayerType> = None;
+    let mut bottoms = Vec::new();
+    let mut tops = Vec::new();
+    let mut propagate_down = Vec::new();
+    let mut params = Vec::new();
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+
+    loop {
+        let line = lines.next().ok_or_else(|| "unexpected end of input inside layer block".to_owned())?;
+        if line == "}" {
+            break;
+        } else if line == "param {" {
+            params.push(parse_param_block(lines)?);
+        } else if line == "include {" {
+            include.push(parse_net_state_rule(lines)?);
+        } else if line == "exclude {" {
+            exclude.push(parse_net_state_rule(lines)?);
+        } else {
+            let (key, value) = key_value(line).ok_or_else(|| format!("malformed line '{}'", line))?;
+            match key {
+                "name" => name = Some(value.to_owned()),
+                "type" => layer_type = Some(parse_layer_type(value)?),
+                "bottom" => bottoms.push(value.to_owned()),
+                "top" => tops.push(value.to_owned()),
+                "propagate_down" => {
+                    propagate_down.push(value.parse().map_err(|_| format!("invalid propagate_down '{}'", value))?)
+                }
+                other => return Err(format!("unknown layer field '{}'", other)),
+            }
+        }
+    }
+
+    let name = name.ok_or_else(|| "layer block is missing a name".to_owned())?;
+    let layer_type = layer_type.ok_or_else(|| format!("layer '{}' is missing a type", name))?;
+
+    let mut config = LayerConfig::new(name, layer_type);
+    for bottom in &bottoms {
+        config.add_bottom(bottom);
+    }
+    for top in &tops {
+        config.add_top(top);
+    }
+    config.propagate_down = propagate_down;
+    for param in params {
+        config.add_param(param);
+    }
+    config.include = include;
+    config.exclude = exclude;
+
+    Ok(config)
+}
+
+fn parse_param_block<'a, I: Iterator<Item = &'a str>>(lines: &mut I) -> Result<ParamConfig, String> {
+    let mut param = ParamConfig::default();
+
+    loop {
+        let line = lines.next().ok_or_else(|| "unexpected end of input inside param block".to_owned())?;
+        if line == "}" {
+            return Ok(param);
+        }
+
+        let (key, value) = key_value(line).ok_or_else(|| format!("malformed line '{}'", line))?;
+        match key {
+            "name" => param.name = value.to_owned(),
+            "share_mode" => param.share_mode = parse_share_mode(value)?,
+            "lr_mult" => param.lr_mult = Some(value.parse().map_err(|_| format!("invalid lr_mult '{}'", value))?),
+            "decay_mult" => {
+                param.decay_mult = Some(value.parse().map_err(|_| format!("invalid decay_mult '{}'", value))?)
+            }
+            other => return Err(format!("unknown param field '{}'", other)),
+        }
+    }
+}
+
+fn param_key(layer_name: &str, param_id: usize, param_config: &ParamConfig) -> String {
+    if param_config.name.is_empty() {
+        format!("{}.param_{}", layer_name, param_id)
+    } else {
+        param_config.name.clone()
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_u32(writer, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_bytes(writer, value.as_bytes())
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?).map_err(|err| invalid_data(err.to_string()))
+}
+
+fn write_shape<W: Write>(writer: &mut W, shape: &[usize]) -> io::Result<()> {
+    write_u32(writer, shape.len() as u32)?;
+    for &dim in shape {
+        writer.write_all(&(dim as u64).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_shape<R: Read>(reader: &mut R) -> io::Result<Vec<usize>> {
+    let len = read_u32(reader)?;
+    let mut shape = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let mut bytes = [0u8; 8];
+        reader.read_exact(&mut bytes)?;
+        shape.push(u64::from_le_bytes(bytes) as usize);
+    }
+    Ok(shape)
+}
+
+fn write_data<W: Write>(writer: &mut W, data: &[f32]) -> io::Result<()> {
+    write_u32(writer, data.len() as u32)?;
+    for &value in data {
+        writer.write_all(&value.to_bits().to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_data<R: Read>(reader: &mut R) -> io::Result<Vec<f32>> {
+    let len = read_u32(reader)?;
+    let mut data = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let mut bytes = [0u8; 4];
+        reader.read_exact(&mut bytes)?;
+        data.push(f32::from_bits(u32::from_le_bytes(bytes)));
+    }
+    Ok(data)
+}
+
+impl<'a> Network<'a> {
+    /// Writes a compact binary checkpoint to `path`: this net's topology
+    /// (the `NetworkConfig` it was built from, not the post-`insert_splits`
+    /// `effective_layers`) followed by every param Blob's shape and data,
+    /// keyed by its `ParamConfig::name`, or by `"<layer>.param_<id>"` for
+    /// an unnamed param.
+    ///
+    /// `NetworkConfig::load_checkpoint_topology` plus `load_weights`
+    /// reconstruct an equivalent net from the file this writes.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = File::create(path)?;
+
+        write_string(&mut file, &self.config.to_text())?;
+
+        // A param shared by several layers (`setup_params`) is the very
+        // same `ArcLock` under every one of them, so it would otherwise be
+        // written out once per sharing layer; `seen_keys` keeps each unique
+        // param in the checkpoint exactly once.
+        let mut seen_keys = HashSet::new();
+        let mut entries = Vec::new();
+        for (layer_id, layer_config) in self.effective_layers.iter().enumerate() {
+            for param_id in 0..layer_config.params_len() {
+                let param_config = layer_config.param(param_id).unwrap();
+                let key = param_key(&layer_config.name, param_id, param_config);
+                if !seen_keys.insert(key.clone()) {
+                    continue;
+                }
+                let blob = self.layers[layer_id].blobs[param_id].read().unwrap();
+                entries.push((key, blob.shape().to_owned(), blob.cpu_data().to_owned()));
+            }
+        }
+
+        write_u32(&mut file, entries.len() as u32)?;
+        for (key, shape, data) in entries {
+            write_string(&mut file, &key)?;
+            write_shape(&mut file, &shape)?;
+            write_data(&mut file, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads every param Blob saved by `save` into this (already-built) net,
+    /// matching by the param's `ParamConfig::name` (falling back to
+    /// `"<layer>.param_<id>"` for an unnamed param) rather than by position.
+    /// A saved param with no matching layer in this net, or a layer param
+    /// with no matching entry in the checkpoint, is left untouched -- this
+    /// is what lets a checkpoint be loaded into a renamed or finetuned net.
+    ///
+    /// `self` must already have been built (via `Network::from_config`)
+    /// from a `NetworkConfig` with the layers you want populated --
+    /// typically the one `NetworkConfig::load_checkpoint_topology` parses
+    /// back out of this same file, for a net loaded fresh rather than
+    /// finetuned/renamed.
+    pub fn load_weights<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut file = File::open(path)?;
+
+        // Already available via `NetworkConfig::load_checkpoint_topology`
+        // for a caller that wants to rebuild a `Network` from scratch;
+        // this net's own topology was already fixed when it was built.
+        let _topology = read_string(&mut file)?;
+
+        let count = read_u32(&mut file)?;
+        let mut saved: Vec<(String, Vec<usize>, Vec<f32>)> = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let key = read_string(&mut file)?;
+            let shape = read_shape(&mut file)?;
+            let data = read_data(&mut file)?;
+            saved.push((key, shape, data));
+        }
+
+        for (layer_id, layer_config) in self.effective_layers.iter().enumerate() {
+            for param_id in 0..layer_config.params_len() {
+                let param_config = layer_config.param(param_id).unwrap();
+                let key = param_key(&layer_config.name, param_id, param_config);
+
+                let found = saved.iter().find(|entry| entry.0 == key);
+                if let Some(&(_, ref shape, ref data)) = found {
+                    let mut blob = self.layers[layer_id].blobs[param_id].write().unwrap();
+                    blob.reshape(shape.clone());
+
+                    let dest = blob.mut_cpu_data();
+                    if dest.len() != data.len() {
+                        return Err(invalid_data(format!("checkpoint entry '{}' has {} value(s), but its Blob \
+                                                          shape {:?} holds {}",
+                                                         key,
+                                                         data.len(),
+                                                         shape,
+                                                         dest.len())));
+                    }
+                    dest.copy_from_slice(data);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}